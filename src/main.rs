@@ -1,16 +1,91 @@
-use axum::{routing::get, Router};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
 use clap::Parser;
 use csv::ReaderBuilder;
-use prometheus::{Encoder, Gauge, IntCounter, IntGauge, Registry, TextEncoder};
+use flate2::read::{DeflateDecoder, GzDecoder};
+use glob::Pattern;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use prometheus::{CounterVec, Encoder, GaugeVec, IntCounter, IntGauge, Opts, Registry, TextEncoder};
 use reqwest::Client;
+use serde::Deserialize;
 use serde_json::Value;
 use serde_yaml;
 use slog::{o, Drain, Level, Logger};
 use slog_term;
+use std::collections::HashSet;
+use std::io::Read;
 use std::str::FromStr;
 use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use subtle::ConstantTimeEq;
+use tokio::io::AsyncReadExt;
 use tokio::{fs, sync::RwLock, time};
 
+/// The metric store. Every metric name maps to its `MetricHandle` plus the
+/// label names it was registered with, so a later lookup can detect a
+/// mismatched label set instead of handing back a handle of the wrong
+/// arity. Unlabeled metrics simply have an empty label-name list and are
+/// accessed with `with_label_values(&[])`.
+type MetricStore = HashMap<String, (MetricHandle, Vec<String>)>;
+
+/// For each labeled metric name, the label-value tuples that were present as
+/// of the last read of the source(s) that populate it. Used to prune series
+/// whose label values disappear between reads (e.g. a CSV row removed).
+type ActiveLabelSets = HashMap<String, HashSet<Vec<String>>>;
+
+/// The most recent absolute value fed into each counter series, keyed by
+/// metric name and label values. Needed because `Counter` only supports
+/// `inc_by`, but sources hand us an absolute reading each time. Keyed by the
+/// label values themselves (not a joined string) so a label value containing
+/// the join separator can't collide with a different label tuple.
+type CounterBaselines = HashMap<(String, Vec<String>), f64>;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum MetricType {
+    #[default]
+    Gauge,
+    Counter,
+}
+
+/// One entry from `--metric-config`: a glob matched against flattened metric
+/// keys, declaring how matching metrics should be typed and presented.
+#[derive(Debug, Deserialize)]
+struct MetricTypeRule {
+    pattern: String,
+    #[serde(default)]
+    r#type: MetricType,
+    #[serde(default)]
+    unit: Option<String>,
+    #[serde(default)]
+    help: Option<String>,
+}
+
+/// A registered metric series, either a freely-settable `Gauge` or a
+/// `Counter` that only ever moves forward.
+#[derive(Clone)]
+enum MetricHandle {
+    Gauge(GaugeVec),
+    Counter(CounterVec),
+}
+
+impl MetricHandle {
+    fn remove_label_values(&self, label_values: &[&str]) {
+        let result = match self {
+            MetricHandle::Gauge(gauge_vec) => gauge_vec.remove_label_values(label_values),
+            MetricHandle::Counter(counter_vec) => counter_vec.remove_label_values(label_values),
+        };
+        let _ = result;
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -26,9 +101,39 @@ struct Args {
     #[arg(long, env = "EPI_IGNORE_KEYS", num_args = 1.., value_delimiter = ',')]
     ignore_keys: Vec<String>,
 
+    #[arg(long, env = "EPI_LABEL_KEYS", num_args = 1.., value_delimiter = ',')]
+    label_keys: Vec<String>,
+
+    #[arg(long, env = "EPI_CSV_LABEL_COLUMN")]
+    csv_label_column: Option<String>,
+
+    #[arg(long, env = "EPI_EXEC_FORMAT")]
+    exec_format: Option<String>,
+
+    #[arg(long, env = "EPI_EXEC_TIMEOUT", default_value_t = 30)]
+    exec_timeout: u64,
+
+    #[arg(long, env = "EPI_METRIC_CONFIG")]
+    metric_config: Option<String>,
+
+    #[arg(long, env = "EPI_HTTP_HEADERS", action = clap::ArgAction::Append, value_delimiter = '\n')]
+    header: Vec<String>,
+
+    #[arg(long, env = "EPI_BEARER_TOKEN")]
+    bearer_token: Option<String>,
+
+    #[arg(long, env = "EPI_BASIC_AUTH")]
+    basic_auth: Option<String>,
+
+    #[arg(long, env = "EPI_HTTP_TIMEOUT", default_value_t = 30)]
+    http_timeout: u64,
+
     #[arg(long, env = "EPI_INTERVAL", default_value_t = 60)]
     interval: u64,
 
+    #[arg(long, env = "EPI_WATCH", default_value_t = false)]
+    watch: bool,
+
     #[arg(long, env = "EPI_METRIC_PREFIX", default_value = "")]
     metric_prefix: String,
 
@@ -37,6 +142,110 @@ struct Args {
 
     #[arg(long, env = "EPI_LOG_LEVEL", default_value = "info")]
     log_level: String,
+
+    #[arg(long, env = "EPI_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<String>,
+
+    #[arg(long, env = "EPI_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    #[arg(long, env = "EPI_AUTH_TOKEN")]
+    auth_token: Option<String>,
+}
+
+/// Config that's the same for every source, cloned once per watch/poll task.
+#[derive(Clone)]
+struct SourceOptions {
+    ignore_keys: Vec<String>,
+    label_keys: Vec<String>,
+    csv_label_column: Option<String>,
+    metric_config: Arc<Vec<MetricTypeRule>>,
+}
+
+impl SourceOptions {
+    fn new(args: &Args, metric_config: Vec<MetricTypeRule>) -> Self {
+        SourceOptions {
+            ignore_keys: args.ignore_keys.clone(),
+            label_keys: args.label_keys.clone(),
+            csv_label_column: args.csv_label_column.clone(),
+            metric_config: Arc::new(metric_config),
+        }
+    }
+}
+
+/// Looks up the first `--metric-config` rule whose glob matches `key`.
+fn resolve_metric_rule<'a>(rules: &'a [MetricTypeRule], key: &str) -> Option<&'a MetricTypeRule> {
+    rules
+        .iter()
+        .find(|rule| Pattern::new(&rule.pattern).map(|p| p.matches(key)).unwrap_or(false))
+}
+
+/// Loads the sidecar YAML config mapping metric-key globs to a declared
+/// type/unit/help, if `--metric-config` was given.
+async fn load_metric_config(path: &str, log: &Logger) -> Vec<MetricTypeRule> {
+    match fs::read_to_string(path).await {
+        Ok(contents) => match serde_yaml::from_str::<Vec<MetricTypeRule>>(&contents) {
+            Ok(rules) => rules,
+            Err(e) => {
+                slog::error!(log, "Failed to parse metric config, ignoring it"; "path" => path, "error" => %e);
+                Vec::new()
+            }
+        },
+        Err(e) => {
+            slog::error!(log, "Failed to read metric config, ignoring it"; "path" => path, "error" => %e);
+            Vec::new()
+        }
+    }
+}
+
+/// Per-source HTTP fetch configuration: extra static headers (baked into the
+/// client) and auth (applied per-request via reqwest's own helpers).
+#[derive(Clone)]
+struct HttpOptions {
+    headers: Vec<(String, String)>,
+    bearer_token: Option<String>,
+    basic_auth: Option<(String, String)>,
+    timeout: u64,
+}
+
+impl HttpOptions {
+    fn new(args: &Args) -> Self {
+        let headers = args
+            .header
+            .iter()
+            .filter_map(|header| header.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        let basic_auth = args.basic_auth.as_ref().and_then(|auth| {
+            auth.split_once(':')
+                .map(|(user, pass)| (user.to_string(), pass.to_string()))
+        });
+
+        HttpOptions {
+            headers,
+            bearer_token: args.bearer_token.clone(),
+            basic_auth,
+            timeout: args.http_timeout,
+        }
+    }
+
+    fn build_client(&self) -> reqwest::Result<Client> {
+        let mut header_map = reqwest::header::HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                reqwest::header::HeaderName::from_bytes(name.as_bytes()),
+                reqwest::header::HeaderValue::from_str(value),
+            ) {
+                header_map.insert(name, value);
+            }
+        }
+
+        Client::builder()
+            .default_headers(header_map)
+            .timeout(Duration::from_secs(self.timeout))
+            .build()
+    }
 }
 
 struct InternalMetrics {
@@ -87,6 +296,19 @@ impl InternalMetrics {
     }
 }
 
+/// Shared handles threaded through every source-processing task and the
+/// `/metrics` handler.
+#[derive(Clone)]
+struct AppState {
+    metrics: Arc<RwLock<MetricStore>>,
+    active_label_sets: Arc<RwLock<ActiveLabelSets>>,
+    counter_baselines: Arc<RwLock<CounterBaselines>>,
+    registry: Registry,
+    metric_prefix: String,
+    log: Logger,
+    internal_metrics: Arc<InternalMetrics>,
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
@@ -97,38 +319,136 @@ async fn main() {
     slog::info!(log, "Starting epimetheus"; "listen_addr" => &args.listen_addr, "port" => args.port);
 
     let registry = Registry::new();
-    let metrics = Arc::new(RwLock::new(HashMap::new()));
     let internal_metrics = Arc::new(InternalMetrics::new(&registry));
-
     internal_metrics.sources_total.set(args.files.len() as i64);
 
-    let update_log = log.clone();
-    tokio::spawn(update_metrics(
-        args.files.clone(),
-        args.ignore_keys.clone(),
-        args.interval,
-        Arc::clone(&metrics),
-        registry.clone(),
-        args.metric_prefix.clone(),
-        update_log,
-        Arc::clone(&internal_metrics),
-    ));
-
-    let app = Router::new()
+    let state = AppState {
+        metrics: Arc::new(RwLock::new(HashMap::new())),
+        active_label_sets: Arc::new(RwLock::new(HashMap::new())),
+        counter_baselines: Arc::new(RwLock::new(HashMap::new())),
+        registry,
+        metric_prefix: args.metric_prefix.clone(),
+        log: log.clone(),
+        internal_metrics,
+    };
+
+    let metric_config = match &args.metric_config {
+        Some(path) => load_metric_config(path, &log).await,
+        None => Vec::new(),
+    };
+    let options = SourceOptions::new(&args, metric_config);
+    let http_options = HttpOptions::new(&args);
+
+    let mut http_files = Vec::new();
+    let mut exec_sources = Vec::new();
+    let mut local_files = Vec::new();
+    for file in &args.files {
+        if is_http_source(file) {
+            http_files.push(file.clone());
+        } else if is_exec_source(file) {
+            exec_sources.push(file.clone());
+        } else {
+            local_files.push(file.clone());
+        }
+    }
+
+    if !http_files.is_empty() {
+        tokio::spawn(poll_http_sources(
+            http_files,
+            options.clone(),
+            args.interval,
+            http_options.clone(),
+            state.clone(),
+        ));
+    }
+
+    if !exec_sources.is_empty() {
+        tokio::spawn(poll_exec_sources(
+            exec_sources,
+            options.clone(),
+            args.interval,
+            args.exec_format.clone(),
+            args.exec_timeout,
+            state.clone(),
+        ));
+    }
+
+    if !local_files.is_empty() {
+        if args.watch {
+            tokio::spawn(watch_local_sources(
+                local_files,
+                options.clone(),
+                args.interval,
+                state.clone(),
+            ));
+        } else {
+            tokio::spawn(poll_local_sources(
+                local_files,
+                options.clone(),
+                args.interval,
+                state.clone(),
+            ));
+        }
+    }
+
+    let mut app = Router::new()
         .route("/metrics", get(metrics_handler))
-        .with_state((
-            registry,
-            metrics,
-            log.clone(),
-            Arc::clone(&internal_metrics),
+        .with_state(state);
+
+    if let Some(token) = &args.auth_token {
+        app = app.route_layer(middleware::from_fn_with_state(
+            Arc::new(token.clone()),
+            auth_middleware,
         ));
+    }
 
-    let addr = format!("{}:{}", args.listen_addr, args.port);
-    slog::info!(log, "Listening"; "address" => &addr);
-    axum::Server::bind(&addr.parse().unwrap())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let addr: std::net::SocketAddr = format!("{}:{}", args.listen_addr, args.port).parse().unwrap();
+
+    match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            slog::info!(log, "Listening over TLS"; "address" => addr.to_string());
+            let config = RustlsConfig::from_pem_file(cert, key)
+                .await
+                .expect("failed to load TLS cert/key");
+            axum_server::bind_rustls(addr, config)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+        _ => {
+            slog::info!(log, "Listening"; "address" => addr.to_string());
+            axum::Server::bind(&addr)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// Gates every request behind `Authorization: Bearer <--auth-token>` when an
+/// auth token is configured, so the exporter can be scraped over untrusted
+/// networks without a reverse proxy in front of it.
+async fn auth_middleware(
+    State(expected_token): State<Arc<String>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let expected_header = format!("Bearer {}", expected_token);
+    let authorized = req
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value.len() == expected_header.len()
+                && value.as_bytes().ct_eq(expected_header.as_bytes()).into()
+        })
+        .unwrap_or(false);
+
+    if authorized {
+        next.run(req).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
 }
 
 fn setup_logger(format: &str, level: Level) -> Logger {
@@ -159,117 +479,432 @@ fn setup_logger(format: &str, level: Level) -> Logger {
     Logger::root(drain, o!("version" => env!("CARGO_PKG_VERSION")))
 }
 
-async fn update_metrics(
-    files: Vec<String>,
-    ignore_keys: Vec<String>,
+fn is_http_source(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+fn is_exec_source(source: &str) -> bool {
+    source.starts_with("exec:")
+}
+
+/// Polls `http(s)://` sources on a fixed interval, since there's nothing on the
+/// filesystem to watch for those.
+async fn poll_http_sources(
+    urls: Vec<String>,
+    options: SourceOptions,
     interval: u64,
-    metrics: Arc<RwLock<HashMap<String, Gauge>>>,
-    registry: Registry,
-    metric_prefix: String,
-    log: Logger,
-    internal_metrics: Arc<InternalMetrics>,
+    http_options: HttpOptions,
+    state: AppState,
 ) {
-    let mut interval = time::interval(Duration::from_secs(interval));
-    let client = Client::new();
+    let mut ticker = time::interval(Duration::from_secs(interval));
+    let client = http_options.build_client().unwrap_or_else(|e| {
+        slog::error!(state.log, "Failed to build HTTP client, falling back to default"; "error" => %e);
+        Client::new()
+    });
 
     loop {
-        interval.tick().await;
+        ticker.tick().await;
 
-        let mut metric_count: i64 = 0;
+        for url in &urls {
+            slog::debug!(state.log, "Processing url"; "url" => url);
+            state.internal_metrics.source_reads_total.inc();
 
-        for file in &files {
-            slog::debug!(log, "Processing file"; "file" => file);
-            internal_metrics.source_reads_total.inc();
-
-            let (contents, file_type) = if file.starts_with("http://")
-                || file.starts_with("https://")
-            {
-                match fetch_url(&client, file, &log).await {
-                    Ok((content, detected_type)) => (content, detected_type),
-                    Err(e) => {
-                        slog::error!(log, "Error fetching URL"; "url" => file, "error" => %e);
-                        internal_metrics.source_read_failures_total.inc();
-                        continue;
-                    }
+            match fetch_url(&client, url, &http_options, &state.log).await {
+                Ok((contents, file_type)) => {
+                    dispatch_contents(&contents, &file_type, &options, &state).await;
                 }
-            } else {
-                match fs::read_to_string(file).await {
-                    Ok(contents) => {
-                        let path = PathBuf::from(file);
-                        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
-                        slog::debug!(log, "Local file read successfully"; "file" => file, "extension" => extension);
-                        (contents, extension.to_string())
-                    }
-                    Err(e) => {
-                        slog::error!(log, "Error reading file"; "file" => file, "error" => %e);
-                        internal_metrics.source_read_failures_total.inc();
-                        continue;
-                    }
+                Err(e) => {
+                    slog::error!(state.log, "Error fetching URL"; "url" => url, "error" => %e);
+                    state.internal_metrics.source_read_failures_total.inc();
                 }
-            };
+            }
+
+            state
+                .internal_metrics
+                .metrics_total
+                .set(state.metrics.read().await.len() as i64);
+        }
+    }
+}
+
+/// Runs `exec:`-prefixed commands on a fixed interval and feeds their stdout
+/// into the JSON/YAML/CSV dispatch, the same as a file or URL source would.
+/// Commands in the list run one at a time, so a single command can never be
+/// invoked again before its previous run has finished.
+async fn poll_exec_sources(
+    commands: Vec<String>,
+    options: SourceOptions,
+    interval: u64,
+    exec_format: Option<String>,
+    exec_timeout: u64,
+    state: AppState,
+) {
+    let mut ticker = time::interval(Duration::from_secs(interval));
+
+    loop {
+        ticker.tick().await;
+
+        for command in &commands {
+            run_exec_source(command, exec_format.as_deref(), exec_timeout, &options, &state).await;
+        }
+    }
+}
+
+async fn run_exec_source(
+    source: &str,
+    exec_format: Option<&str>,
+    exec_timeout: u64,
+    options: &SourceOptions,
+    state: &AppState,
+) {
+    let command = source.strip_prefix("exec:").unwrap_or(source);
+    slog::debug!(state.log, "Running exec source"; "command" => command);
+    state.internal_metrics.source_reads_total.inc();
 
-            slog::debug!(log, "Processing content"; "file" => file, "file_type" => &file_type);
-            match file_type.as_str() {
-                "json" => {
-                    metric_count += process_json(
-                        &contents,
-                        &ignore_keys,
-                        &metrics,
-                        &registry,
-                        &metric_prefix,
-                        &log,
-                        &internal_metrics,
-                    )
-                    .await
+    let child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            slog::error!(state.log, "Failed to spawn exec source"; "command" => command, "error" => %e);
+            state.internal_metrics.source_read_failures_total.inc();
+            return;
+        }
+    };
+
+    let mut stdout_pipe = child.stdout.take();
+    let mut stderr_pipe = child.stderr.take();
+
+    // Drain stdout/stderr concurrently with `wait()`, not after it: once
+    // either stream's output exceeds the OS pipe buffer (64KB on Linux),
+    // the child blocks on `write()` until something reads the pipe, so
+    // waiting first and reading after deadlocks on any command with
+    // substantial output.
+    let wait_result = {
+        let child = &mut child;
+        time::timeout(Duration::from_secs(exec_timeout), async move {
+            let mut stdout_buf = Vec::new();
+            let mut stderr_buf = Vec::new();
+            let stdout_fut = async {
+                if let Some(pipe) = stdout_pipe.as_mut() {
+                    let _ = pipe.read_to_end(&mut stdout_buf).await;
                 }
-                "yaml" | "yml" => {
-                    metric_count += process_yaml(
-                        &contents,
-                        &ignore_keys,
-                        &metrics,
-                        &registry,
-                        &metric_prefix,
-                        &log,
-                        &internal_metrics,
-                    )
-                    .await
+            };
+            let stderr_fut = async {
+                if let Some(pipe) = stderr_pipe.as_mut() {
+                    let _ = pipe.read_to_end(&mut stderr_buf).await;
                 }
-                "csv" => {
-                    metric_count += process_csv(
-                        &contents,
-                        &ignore_keys,
-                        &metrics,
-                        &registry,
-                        &metric_prefix,
-                        &log,
-                        &internal_metrics,
-                    )
-                    .await
+            };
+            let (status, _, _) = tokio::join!(child.wait(), stdout_fut, stderr_fut);
+            (status, stdout_buf, stderr_buf)
+        })
+        .await
+    };
+
+    let (status, stdout_buf, stderr_buf) = match wait_result {
+        Ok((Ok(status), stdout_buf, stderr_buf)) => (status, stdout_buf, stderr_buf),
+        Ok((Err(e), _, _)) => {
+            slog::error!(state.log, "Failed to run exec source"; "command" => command, "error" => %e);
+            state.internal_metrics.source_read_failures_total.inc();
+            return;
+        }
+        Err(_) => {
+            slog::error!(state.log, "Exec source timed out, killing it"; "command" => command, "timeout_secs" => exec_timeout);
+            if let Err(e) = child.kill().await {
+                slog::error!(state.log, "Failed to kill timed-out exec source"; "command" => command, "error" => %e);
+            }
+            state.internal_metrics.source_read_failures_total.inc();
+            return;
+        }
+    };
+
+    let stderr_buf = String::from_utf8_lossy(&stderr_buf).into_owned();
+
+    if !status.success() {
+        slog::error!(
+            state.log, "Exec source exited non-zero";
+            "command" => command,
+            "status" => status.code().unwrap_or(-1),
+            "stderr" => stderr_buf.trim(),
+        );
+        state.internal_metrics.source_read_failures_total.inc();
+        return;
+    }
+
+    let stdout = String::from_utf8_lossy(&stdout_buf).into_owned();
+    let (file_type, content) = detect_exec_format(&stdout, exec_format);
+    dispatch_contents(content, &file_type, options, state).await;
+
+    state
+        .internal_metrics
+        .metrics_total
+        .set(state.metrics.read().await.len() as i64);
+}
+
+/// Picks the format to parse a command's stdout as: the explicit
+/// `--exec-format` hint if given, otherwise a `#json`/`#yaml`/`#csv`
+/// shebang-style marker on the first line (stripped before parsing),
+/// otherwise `json`.
+fn detect_exec_format<'a>(output: &'a str, hint: Option<&str>) -> (String, &'a str) {
+    if let Some(hint) = hint {
+        return (hint.to_string(), output);
+    }
+
+    if let Some(first_line) = output.lines().next() {
+        if let Some(marker) = first_line.strip_prefix('#') {
+            let marker = marker.trim().to_lowercase();
+            if matches!(marker.as_str(), "json" | "yaml" | "yml" | "csv") {
+                let rest = output.splitn(2, '\n').nth(1).unwrap_or("");
+                return (marker, rest);
+            }
+        }
+    }
+
+    ("json".to_string(), output)
+}
+
+/// Re-reads local-file sources on a fixed interval. Used directly when `--watch`
+/// is off, and as a fallback for any individual source a filesystem watcher
+/// couldn't be established for.
+async fn poll_local_sources(
+    files: Vec<String>,
+    options: SourceOptions,
+    interval: u64,
+    state: AppState,
+) {
+    let mut ticker = time::interval(Duration::from_secs(interval));
+
+    loop {
+        ticker.tick().await;
+
+        for file in &files {
+            reprocess_local_file(file, &options, &state).await;
+        }
+    }
+}
+
+/// Watches local-file sources with `notify` and re-parses just the file that
+/// changed as soon as it settles, instead of waiting out a fixed interval.
+/// Rapid successive events for the same path (editors that write-then-rename)
+/// are coalesced into a single re-parse, made once 200ms pass with no new
+/// event for that path. Any source a
+/// watcher can't be registered for falls back to interval polling.
+async fn watch_local_sources(
+    files: Vec<String>,
+    options: SourceOptions,
+    fallback_interval: u64,
+    state: AppState,
+) {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<PathBuf>();
+
+    let watcher = RecommendedWatcher::new(
+        move |res: notify::Result<Event>| {
+            if let Ok(event) = res {
+                if matches!(
+                    event.kind,
+                    notify::EventKind::Modify(_) | notify::EventKind::Create(_)
+                ) {
+                    for path in event.paths {
+                        let _ = tx.send(path);
+                    }
                 }
-                _ => {
-                    slog::warn!(log, "Unsupported file format"; "file" => file, "file_type" => file_type)
+            }
+        },
+        notify::Config::default(),
+    );
+
+    let mut watcher = match watcher {
+        Ok(w) => w,
+        Err(e) => {
+            slog::warn!(state.log, "Failed to initialize filesystem watcher, falling back to polling"; "error" => %e);
+            return poll_local_sources(files, options, fallback_interval, state).await;
+        }
+    };
+
+    let mut file_by_path: HashMap<PathBuf, String> = HashMap::new();
+    let mut unwatchable: Vec<String> = Vec::new();
+    let mut watched_dirs: HashSet<PathBuf> = HashSet::new();
+
+    // Watch each source's parent directory rather than the file itself: many
+    // editors (vim, most IDEs) save by writing a temp file and renaming it
+    // over the original, which replaces the inode the watch was registered
+    // on and silently stops all further events for that path. Watching the
+    // directory and filtering by filename survives that rename.
+    for file in &files {
+        let path = PathBuf::from(file);
+        let dir = match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            Some(parent) => parent.to_path_buf(),
+            None => PathBuf::from("."),
+        };
+
+        let watch_result = if watched_dirs.contains(&dir) {
+            Ok(())
+        } else {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)
+        };
+
+        match watch_result {
+            Ok(()) => {
+                // Key off the parent directory's canonical path (it must
+                // exist, since we just watched it) joined with the file
+                // name, rather than canonicalizing the full file path: a
+                // source that doesn't exist yet can't be canonicalized, so
+                // that would fall back to a non-canonical key that never
+                // matches the canonicalized path an incoming event carries
+                // once the file is created.
+                let dir_canonical = dir.canonicalize().unwrap_or_else(|_| dir.clone());
+                let file_name = path.file_name().map(PathBuf::from).unwrap_or_default();
+                watched_dirs.insert(dir);
+                file_by_path.insert(dir_canonical.join(file_name), file.clone());
+            }
+            Err(e) => {
+                slog::warn!(state.log, "Failed to watch source, will poll it instead"; "file" => file, "error" => %e);
+                unwatchable.push(file.clone());
+            }
+        }
+    }
+
+    // Read every source once up front so metrics are populated before the
+    // first filesystem event arrives.
+    for file in &files {
+        reprocess_local_file(file, &options, &state).await;
+    }
+
+    if !unwatchable.is_empty() {
+        tokio::spawn(poll_local_sources(
+            unwatchable,
+            options.clone(),
+            fallback_interval,
+            state.clone(),
+        ));
+    }
+
+    let debounce = Duration::from_millis(200);
+    let mut pending: HashMap<PathBuf, time::Instant> = HashMap::new();
+    let mut debounce_tick = time::interval(Duration::from_millis(50));
+
+    loop {
+        tokio::select! {
+            Some(path) = rx.recv() => {
+                pending.insert(path, time::Instant::now());
+            }
+            _ = debounce_tick.tick() => {
+                let now = time::Instant::now();
+                let ready: Vec<PathBuf> = pending
+                    .iter()
+                    .filter(|(_, seen)| now.duration_since(**seen) >= debounce)
+                    .map(|(path, _)| path.clone())
+                    .collect();
+
+                for path in ready {
+                    pending.remove(&path);
+                    let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+                    if let Some(file) = file_by_path.get(&canonical).cloned() {
+                        reprocess_local_file(&file, &options, &state).await;
+                    }
                 }
             }
         }
-        internal_metrics.metrics_total.set(metric_count);
+    }
+}
+
+async fn reprocess_local_file(file: &str, options: &SourceOptions, state: &AppState) {
+    slog::debug!(state.log, "Processing file"; "file" => file);
+    state.internal_metrics.source_reads_total.inc();
+
+    match fs::read_to_string(file).await {
+        Ok(contents) => {
+            let path = PathBuf::from(file);
+            let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            slog::debug!(state.log, "Local file read successfully"; "file" => file, "extension" => extension);
+            dispatch_contents(&contents, extension, options, state).await;
+        }
+        Err(e) => {
+            slog::error!(state.log, "Error reading file"; "file" => file, "error" => %e);
+            state.internal_metrics.source_read_failures_total.inc();
+        }
+    }
+
+    state
+        .internal_metrics
+        .metrics_total
+        .set(state.metrics.read().await.len() as i64);
+}
+
+async fn dispatch_contents(contents: &str, file_type: &str, options: &SourceOptions, state: &AppState) {
+    slog::debug!(state.log, "Processing content"; "file_type" => file_type);
+    match file_type {
+        "json" => {
+            process_json(contents, options, state).await;
+        }
+        "yaml" | "yml" => {
+            process_yaml(contents, options, state).await;
+        }
+        "csv" => {
+            process_csv(contents, options, state).await;
+        }
+        _ => {
+            slog::warn!(state.log, "Unsupported file format"; "file_type" => file_type)
+        }
     }
 }
 
 async fn fetch_url(
     client: &Client,
     url: &str,
+    http_options: &HttpOptions,
     log: &Logger,
-) -> Result<(String, String), Box<dyn std::error::Error>> {
+) -> Result<(String, String), Box<dyn std::error::Error + Send + Sync>> {
     slog::debug!(log, "Fetching URL"; "url" => url);
-    let response = client.get(url).send().await?;
+    let mut request = client.get(url);
+    if let Some(token) = &http_options.bearer_token {
+        request = request.bearer_auth(token);
+    }
+    if let Some((user, pass)) = &http_options.basic_auth {
+        request = request.basic_auth(user, Some(pass));
+    }
+    let response = request.send().await?;
 
     let file_type = detect_file_type_from_headers(response.headers());
     slog::debug!(log, "Detected file type from headers"; "url" => url, "file_type" => &file_type);
-    let content = response.text().await?;
+    let content_encoding = response
+        .headers()
+        .get(reqwest::header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_lowercase());
+    let body = response.bytes().await?;
+    let content = decode_body(&body, content_encoding.as_deref())?;
 
     Ok((content, file_type))
 }
 
+/// Decodes an HTTP response body according to its `Content-Encoding` header,
+/// since `reqwest` is built without its own decompression feature here.
+fn decode_body(
+    body: &[u8],
+    content_encoding: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut decoded = String::new();
+    match content_encoding {
+        Some("gzip") => {
+            GzDecoder::new(body).read_to_string(&mut decoded)?;
+        }
+        Some("deflate") => {
+            DeflateDecoder::new(body).read_to_string(&mut decoded)?;
+        }
+        _ => {
+            decoded = String::from_utf8(body.to_vec())?;
+        }
+    }
+    Ok(decoded)
+}
+
 const CSV_TYPES: [&str; 1] = ["text/csv"];
 const JSON_TYPES: [&str; 1] = ["application/json"];
 const YAML_TYPES: [&str; 3] = ["application/yaml", "application/x-yaml", "text/x-yaml"];
@@ -289,79 +924,63 @@ fn detect_file_type_from_headers(headers: &reqwest::header::HeaderMap) -> String
     "unknown".to_string()
 }
 
-async fn process_json(
-    contents: &str,
-    ignore_keys: &[String],
-    metrics: &Arc<RwLock<HashMap<String, Gauge>>>,
-    registry: &Registry,
-    metric_prefix: &str,
-    log: &Logger,
-    internal_metrics: &Arc<InternalMetrics>,
-) -> i64 {
-    slog::debug!(log, "Processing JSON content");
+async fn process_json(contents: &str, options: &SourceOptions, state: &AppState) -> i64 {
+    slog::debug!(state.log, "Processing JSON content");
     if let Ok(json) = serde_json::from_str::<Value>(contents) {
-        let flattened = flatten_json(&json);
-        let metric_count = update_metrics_from_map(
-            &flattened,
-            ignore_keys,
-            metrics,
-            registry,
-            metric_prefix,
-            log,
-            internal_metrics,
-        )
-        .await;
-        return metric_count;
+        return process_flattened(&json, options, state).await;
     } else {
-        slog::error!(log, "Failed to parse JSON content");
+        slog::error!(state.log, "Failed to parse JSON content");
     }
     return 0;
 }
 
-async fn process_yaml(
-    contents: &str,
-    ignore_keys: &[String],
-    metrics: &Arc<RwLock<HashMap<String, Gauge>>>,
-    registry: &Registry,
-    metric_prefix: &str,
-    log: &Logger,
-    internal_metrics: &Arc<InternalMetrics>,
-) -> i64 {
-    slog::debug!(log, "Processing YAML content");
+async fn process_yaml(contents: &str, options: &SourceOptions, state: &AppState) -> i64 {
+    slog::debug!(state.log, "Processing YAML content");
     if let Ok(yaml) = serde_yaml::from_str::<serde_yaml::Value>(contents) {
         if let Ok(json) = serde_json::to_value(yaml) {
-            let flattened = flatten_json(&json);
-            let metric_count = update_metrics_from_map(
-                &flattened,
-                ignore_keys,
-                metrics,
-                registry,
-                metric_prefix,
-                log,
-                internal_metrics,
-            )
-            .await;
-
-            return metric_count;
+            return process_flattened(&json, options, state).await;
         } else {
-            slog::error!(log, "Failed to convert YAML to JSON");
+            slog::error!(state.log, "Failed to convert YAML to JSON");
         }
     } else {
-        slog::error!(log, "Failed to parse YAML content");
+        slog::error!(state.log, "Failed to parse YAML content");
     }
     return 0;
 }
 
-async fn process_csv(
-    contents: &str,
-    ignore_keys: &[String],
-    metrics: &Arc<RwLock<HashMap<String, Gauge>>>,
-    registry: &Registry,
-    metric_prefix: &str,
-    log: &Logger,
-    internal_metrics: &Arc<InternalMetrics>,
-) -> i64 {
-    slog::debug!(log, "Processing CSV content");
+/// Flattens a JSON document and updates both the unlabeled scalar metrics and
+/// the labeled metrics produced by any array-of-objects found within it.
+async fn process_flattened(json: &Value, options: &SourceOptions, state: &AppState) -> i64 {
+    let flattened = flatten_json(json, &options.label_keys);
+
+    let mut metric_count = update_metrics_from_map(
+        &flattened.scalars,
+        &options.ignore_keys,
+        &options.metric_config,
+        state,
+    )
+    .await;
+
+    if !flattened.records.is_empty() {
+        metric_count += update_record_metrics(
+            &flattened.records,
+            &options.label_keys,
+            &options.ignore_keys,
+            &options.metric_config,
+            state,
+        )
+        .await;
+    }
+
+    metric_count
+}
+
+/// With `csv_label_column` set, reads every row of the CSV: each row becomes a
+/// labeled series keyed by that column's value and stale label values (rows
+/// removed from the file since the last read) are pruned. Without it, only
+/// the first row is processed, matching single-record CSV sources.
+async fn process_csv(contents: &str, options: &SourceOptions, state: &AppState) -> i64 {
+    slog::debug!(state.log, "Processing CSV content");
     let mut reader = ReaderBuilder::new()
         .has_headers(true)
         .from_reader(contents.as_bytes());
@@ -369,53 +988,131 @@ async fn process_csv(
     let headers = match reader.headers() {
         Ok(headers) => headers.clone(),
         Err(_) => {
-            slog::error!(log, "Failed to read CSV headers");
+            slog::error!(state.log, "Failed to read CSV headers");
             return 0;
         }
     };
 
-    let first_row = match reader.records().next() {
-        Some(Ok(row)) => row,
-        _ => {
-            slog::error!(log, "Failed to read first CSV row");
-            return 0;
-        }
+    let Some(label_column) = options.csv_label_column.as_deref() else {
+        let first_row = match reader.records().next() {
+            Some(Ok(row)) => row,
+            _ => {
+                slog::error!(state.log, "Failed to read first CSV row");
+                return 0;
+            }
+        };
+
+        let obj: HashMap<String, Value> = headers
+            .iter()
+            .zip(first_row.iter())
+            .filter_map(|(header, value)| {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .map(|num| (header.to_string(), json_number(num)))
+            })
+            .collect();
+
+        return update_metrics_from_map(&obj, &options.ignore_keys, &options.metric_config, state)
+            .await;
     };
 
-    let obj: HashMap<String, Value> = headers
+    let mut metric_count: i64 = 0;
+
+    // Seed every metric name this source can produce, from the headers
+    // alone, before looking at a single row. Otherwise a cycle where every
+    // row fails to parse (or the file is empty) leaves `seen_label_values`
+    // empty, `prune_stale_label_sets` never visits these metric names, and
+    // their previously active label values linger forever.
+    let mut seen_label_values: HashMap<String, HashSet<Vec<String>>> = headers
         .iter()
-        .zip(first_row.iter())
-        .filter_map(|(header, value)| {
-            value.parse::<f64>().ok().map(|num| {
-                (
-                    header.to_string(),
-                    Value::Number(serde_json::Number::from_f64(num).unwrap()),
-                )
-            })
+        .filter(|header| *header != label_column && !options.ignore_keys.iter().any(|k| k == *header))
+        .map(|header| {
+            let rule = resolve_metric_rule(&options.metric_config, header);
+            let unit = rule.and_then(|r| r.unit.as_deref()).unwrap_or("");
+            let metric_name = format!("{}{}{}", state.metric_prefix, header, unit);
+            (metric_name, HashSet::new())
         })
         .collect();
 
-    let metric_count = update_metrics_from_map(
-        &obj,
-        ignore_keys,
-        metrics,
-        registry,
-        metric_prefix,
-        log,
-        internal_metrics,
-    )
-    .await;
+    for result in reader.records() {
+        let row = match result {
+            Ok(row) => row,
+            Err(e) => {
+                slog::warn!(state.log, "Failed to read CSV row"; "error" => %e);
+                continue;
+            }
+        };
+
+        let Some(label_value) = headers
+            .iter()
+            .zip(row.iter())
+            .find(|(header, _)| *header == label_column)
+            .map(|(_, value)| value.to_string())
+        else {
+            slog::warn!(state.log, "CSV row missing label column"; "column" => label_column);
+            continue;
+        };
+
+        for (header, value) in headers.iter().zip(row.iter()) {
+            if header == label_column || options.ignore_keys.iter().any(|k| k == header) {
+                continue;
+            }
+            let Some(n) = value.parse::<f64>().ok() else {
+                continue;
+            };
+
+            let rule = resolve_metric_rule(&options.metric_config, header);
+            let metric_type = rule.map(|r| r.r#type).unwrap_or_default();
+            let unit = rule.and_then(|r| r.unit.as_deref()).unwrap_or("");
+            let help = rule.and_then(|r| r.help.as_deref()).unwrap_or(header);
+            let metric_name = format!("{}{}{}", state.metric_prefix, header, unit);
+
+            let handle = match get_or_create_metric(&metric_name, help, &[label_column], metric_type, state)
+                .await
+            {
+                Some(handle) => handle,
+                None => continue,
+            };
+            set_metric_value(&handle, &metric_name, &[&label_value], n, state).await;
+            metric_count += 1;
+
+            seen_label_values
+                .entry(metric_name)
+                .or_default()
+                .insert(vec![label_value.clone()]);
+        }
+    }
+
+    prune_stale_label_sets(&seen_label_values, state).await;
 
-    return metric_count;
+    metric_count
 }
 
-fn flatten_json(value: &Value) -> HashMap<String, Value> {
-    let mut map = HashMap::new();
-    flatten_json_inner(value, String::new(), &mut map);
-    map
+fn json_number(n: f64) -> Value {
+    Value::Number(serde_json::Number::from_f64(n).unwrap())
+}
+
+struct FlattenResult {
+    scalars: HashMap<String, Value>,
+    records: Vec<HashMap<String, Value>>,
+}
+
+fn flatten_json(value: &Value, label_keys: &[String]) -> FlattenResult {
+    let mut result = FlattenResult {
+        scalars: HashMap::new(),
+        records: Vec::new(),
+    };
+    flatten_json_inner(value, String::new(), label_keys, &mut result);
+    result
 }
 
-fn flatten_json_inner(value: &Value, prefix: String, map: &mut HashMap<String, Value>) {
+fn flatten_json_inner(
+    value: &Value,
+    prefix: String,
+    label_keys: &[String],
+    result: &mut FlattenResult,
+) {
     match value {
         Value::Object(obj) => {
             for (k, v) in obj {
@@ -424,17 +1121,129 @@ fn flatten_json_inner(value: &Value, prefix: String, map: &mut HashMap<String, V
                 } else {
                     format!("{}__{}", prefix, k)
                 };
-                flatten_json_inner(v, new_prefix, map);
+                flatten_json_inner(v, new_prefix, label_keys, result);
             }
         }
         Value::Array(arr) => {
-            for (i, v) in arr.iter().enumerate() {
-                let new_prefix = format!("{}__{}", prefix, i);
-                flatten_json_inner(v, new_prefix, map);
+            if !label_keys.is_empty() && !arr.is_empty() && arr.iter().all(Value::is_object) {
+                for item in arr {
+                    if let Value::Object(obj) = item {
+                        result
+                            .records
+                            .push(obj.iter().map(|(k, v)| (k.clone(), v.clone())).collect());
+                    }
+                }
+            } else {
+                for (i, v) in arr.iter().enumerate() {
+                    let new_prefix = format!("{}__{}", prefix, i);
+                    flatten_json_inner(v, new_prefix, label_keys, result);
+                }
             }
         }
         _ => {
-            map.insert(prefix, value.clone());
+            result.scalars.insert(prefix, value.clone());
+        }
+    }
+}
+
+fn numeric_value(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse::<f64>().ok(),
+        _ => None,
+    }
+}
+
+/// Fetches the `MetricHandle` for `metric_name`, registering it as `metric_type`
+/// with `label_names` on first use. Cloning a `GaugeVec`/`CounterVec` is
+/// cheap; it's a handle onto the same underlying series.
+///
+/// Returns `None` (after logging) if `metric_name` is already registered
+/// with a different label set — e.g. a scalar field and an array-of-objects
+/// record both flattening to the same key. Reusing the existing handle in
+/// that case would call `with_label_values` with the wrong arity, which
+/// panics inside `prometheus`.
+async fn get_or_create_metric(
+    metric_name: &str,
+    help: &str,
+    label_names: &[&str],
+    metric_type: MetricType,
+    state: &AppState,
+) -> Option<MetricHandle> {
+    let mut metrics = state.metrics.write().await;
+
+    if let Some((handle, existing_labels)) = metrics.get(metric_name) {
+        return if existing_labels.iter().map(String::as_str).eq(label_names.iter().copied()) {
+            Some(handle.clone())
+        } else {
+            slog::error!(
+                state.log, "Metric already registered with a different label set, skipping";
+                "metric" => metric_name,
+                "existing_labels" => existing_labels.join(","),
+                "requested_labels" => label_names.join(","),
+            );
+            None
+        };
+    }
+
+    let opts = Opts::new(metric_name, help);
+    let handle = match metric_type {
+        MetricType::Gauge => {
+            let gauge_vec = GaugeVec::new(opts, label_names).unwrap();
+            state.registry.register(Box::new(gauge_vec.clone())).unwrap();
+            MetricHandle::Gauge(gauge_vec)
+        }
+        MetricType::Counter => {
+            let counter_vec = CounterVec::new(opts, label_names).unwrap();
+            state.registry.register(Box::new(counter_vec.clone())).unwrap();
+            MetricHandle::Counter(counter_vec)
+        }
+    };
+
+    metrics.insert(
+        metric_name.to_string(),
+        (handle.clone(), label_names.iter().map(|s| s.to_string()).collect()),
+    );
+    Some(handle)
+}
+
+/// Applies a sampled value to a metric series. Gauges are simply set.
+/// Counters only move forward: the new absolute reading is compared against
+/// the last one seen for this exact series, and a decrease is treated as the
+/// source process having restarted — the counter is rebased to the new
+/// reading rather than the read being dropped, so the series recovers
+/// instead of sticking at its pre-restart value forever.
+async fn set_metric_value(
+    handle: &MetricHandle,
+    metric_name: &str,
+    label_values: &[&str],
+    value: f64,
+    state: &AppState,
+) {
+    match handle {
+        MetricHandle::Gauge(gauge_vec) => {
+            gauge_vec.with_label_values(label_values).set(value);
+        }
+        MetricHandle::Counter(counter_vec) => {
+            let baseline_key = (
+                metric_name.to_string(),
+                label_values.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            );
+            let mut baselines = state.counter_baselines.write().await;
+            let previous = baselines.get(&baseline_key).copied().unwrap_or(0.0);
+
+            if value < previous {
+                slog::warn!(
+                    state.log, "Counter decreased, treating as a source restart and rebasing";
+                    "metric" => metric_name, "previous" => previous, "value" => value,
+                );
+                counter_vec.with_label_values(label_values).inc_by(value);
+                baselines.insert(baseline_key, value);
+                return;
+            }
+
+            counter_vec.with_label_values(label_values).inc_by(value - previous);
+            baselines.insert(baseline_key, value);
         }
     }
 }
@@ -442,65 +1251,121 @@ fn flatten_json_inner(value: &Value, prefix: String, map: &mut HashMap<String, V
 async fn update_metrics_from_map(
     obj: &HashMap<String, Value>,
     ignore_keys: &[String],
-    metrics: &Arc<RwLock<HashMap<String, Gauge>>>,
-    registry: &Registry,
-    metric_prefix: &str,
-    log: &Logger,
-    _internal_metrics: &Arc<InternalMetrics>,
+    metric_config: &[MetricTypeRule],
+    state: &AppState,
 ) -> i64 {
     let mut metrics_count: i64 = 0;
     for (key, value) in obj {
-        if !ignore_keys.contains(key) {
-            let metric_name = format!("{}{}", metric_prefix, key);
-            let mut metrics = metrics.write().await;
-
-            match value {
-                Value::Number(num) => {
-                    if let Some(n) = num.as_f64() {
-                        let gauge = metrics.entry(metric_name.clone()).or_insert_with(|| {
-                            let gauge = Gauge::new(metric_name.clone(), key.clone()).unwrap();
-                            registry.register(Box::new(gauge.clone())).unwrap();
-                            gauge
-                        });
-                        gauge.set(n);
-                        metrics_count += 1;
-                        slog::debug!(log, "Updated numeric metric"; "metric" => &metric_name, "value" => n);
-                    }
-                }
-                Value::String(s) => {
-                    if let Ok(n) = s.parse::<f64>() {
-                        let gauge = metrics.entry(metric_name.clone()).or_insert_with(|| {
-                            let gauge = Gauge::new(metric_name.clone(), key.clone()).unwrap();
-                            registry.register(Box::new(gauge.clone())).unwrap();
-                            gauge
-                        });
-                        gauge.set(n);
-                        metrics_count += 1;
-                        slog::debug!(log, "Updated string metric"; "metric" => &metric_name, "value" => n);
-                    } else {
-                        slog::warn!(log, "Failed to parse string as number"; "metric" => &metric_name, "value" => s);
-                    }
-                }
-                _ => {
-                    slog::warn!(log, "Unsupported value type for metric"; "metric" => &metric_name);
-                }
+        if ignore_keys.contains(key) {
+            continue;
+        }
+
+        let n = match numeric_value(value) {
+            Some(n) => n,
+            None => {
+                slog::warn!(state.log, "Unsupported value for metric"; "metric" => key);
+                continue;
+            }
+        };
+
+        let rule = resolve_metric_rule(metric_config, key);
+        let metric_type = rule.map(|r| r.r#type).unwrap_or_default();
+        let unit = rule.and_then(|r| r.unit.as_deref()).unwrap_or("");
+        let help = rule.and_then(|r| r.help.as_deref()).unwrap_or(key);
+        let metric_name = format!("{}{}{}", state.metric_prefix, key, unit);
+
+        let handle = match get_or_create_metric(&metric_name, help, &[], metric_type, state).await {
+            Some(handle) => handle,
+            None => continue,
+        };
+        set_metric_value(&handle, &metric_name, &[], n, state).await;
+        metrics_count += 1;
+        slog::debug!(state.log, "Updated metric"; "metric" => &metric_name, "value" => n);
+    }
+    return metrics_count;
+}
+
+/// Updates metrics produced by arrays-of-objects: each numeric field in a
+/// record becomes its own series (e.g. `used`), and the record's
+/// `label_keys` fields become that series' label values
+/// (e.g. `used{disk="sda"}`).
+async fn update_record_metrics(
+    records: &[HashMap<String, Value>],
+    label_keys: &[String],
+    ignore_keys: &[String],
+    metric_config: &[MetricTypeRule],
+    state: &AppState,
+) -> i64 {
+    let label_names: Vec<&str> = label_keys.iter().map(String::as_str).collect();
+    let mut metrics_count: i64 = 0;
+
+    for record in records {
+        let label_values: Vec<String> = label_keys
+            .iter()
+            .map(|label| {
+                record
+                    .get(label)
+                    .and_then(Value::as_str)
+                    .unwrap_or("")
+                    .to_string()
+            })
+            .collect();
+        let label_value_refs: Vec<&str> = label_values.iter().map(String::as_str).collect();
+
+        for (key, value) in record {
+            if ignore_keys.contains(key) || label_keys.contains(key) {
+                continue;
             }
+
+            let n = match numeric_value(value) {
+                Some(n) => n,
+                None => continue,
+            };
+
+            let rule = resolve_metric_rule(metric_config, key);
+            let metric_type = rule.map(|r| r.r#type).unwrap_or_default();
+            let unit = rule.and_then(|r| r.unit.as_deref()).unwrap_or("");
+            let help = rule.and_then(|r| r.help.as_deref()).unwrap_or(key);
+            let metric_name = format!("{}{}{}", state.metric_prefix, key, unit);
+
+            let handle = match get_or_create_metric(&metric_name, help, &label_names, metric_type, state).await {
+                Some(handle) => handle,
+                None => continue,
+            };
+            set_metric_value(&handle, &metric_name, &label_value_refs, n, state).await;
+            metrics_count += 1;
+            slog::debug!(state.log, "Updated labeled metric"; "metric" => &metric_name, "value" => n);
         }
     }
+
     return metrics_count;
 }
 
-async fn metrics_handler(
-    state: axum::extract::State<(
-        Registry,
-        Arc<RwLock<HashMap<String, Gauge>>>,
-        Logger,
-        Arc<InternalMetrics>,
-    )>,
-) -> String {
-    slog::debug!(state.2, "Handling metrics request");
+/// Removes label-value tuples that were active as of the previous read of a
+/// source but weren't seen in the current one, then records the current set
+/// as the new baseline.
+async fn prune_stale_label_sets(seen: &HashMap<String, HashSet<Vec<String>>>, state: &AppState) {
+    let mut active = state.active_label_sets.write().await;
+    let metrics = state.metrics.read().await;
+
+    for (metric_name, seen_values) in seen {
+        let previously_active = active.entry(metric_name.clone()).or_default();
+
+        if let Some((handle, _)) = metrics.get(metric_name) {
+            for stale_values in previously_active.difference(seen_values) {
+                let refs: Vec<&str> = stale_values.iter().map(String::as_str).collect();
+                handle.remove_label_values(&refs);
+            }
+        }
+
+        *previously_active = seen_values.clone();
+    }
+}
+
+async fn metrics_handler(state: axum::extract::State<AppState>) -> String {
+    slog::debug!(state.log, "Handling metrics request");
     let encoder = TextEncoder::new();
-    let metric_families = state.0 .0.gather();
+    let metric_families = state.registry.gather();
     let mut buffer = vec![];
     encoder.encode(&metric_families, &mut buffer).unwrap();
     String::from_utf8(buffer).unwrap()